@@ -63,29 +63,95 @@ fn extract_metadata(path: &str) -> PyResult<String> {
     Ok(serde_json::to_string(&metadata).unwrap_or_else(|_| "{}".to_string()))
 }
 
+/// VOI LUT functions defined by DICOM PS3.3 C.11.2.1.2.
+enum VoiFunction {
+    Linear,
+    LinearExact,
+    Sigmoid,
+}
+
+impl VoiFunction {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "LINEAR" => Ok(Self::Linear),
+            "LINEAR_EXACT" => Ok(Self::LinearExact),
+            "SIGMOID" => Ok(Self::Sigmoid),
+            other => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unsupported VOI LUT function: {other}"
+            ))),
+        }
+    }
+}
+
+/// Modality LUT stage: rescales a stored pixel value into the units the
+/// VOI LUT expects (e.g. Hounsfield units for CT).
+fn apply_modality_lut(pixel: f32, rescale_slope: f32, rescale_intercept: f32) -> f32 {
+    pixel * rescale_slope + rescale_intercept
+}
+
+fn linear_map(value: f32, lower: f32, upper: f32, out_max: f32) -> f32 {
+    ((value.clamp(lower, upper) - lower) / (upper - lower)) * out_max
+}
+
+/// VOI LUT stage, applied after the modality LUT, per DICOM PS3.3 C.11.2.1.2.
+fn apply_voi(value: f32, window_width: f32, window_center: f32, out_max: f32, function: &VoiFunction) -> f32 {
+    match function {
+        VoiFunction::Linear => {
+            let lower = window_center - 0.5 - (window_width - 1.0) / 2.0;
+            let upper = window_center - 0.5 + (window_width - 1.0) / 2.0;
+            linear_map(value, lower, upper, out_max)
+        }
+        VoiFunction::LinearExact => {
+            let lower = window_center - window_width / 2.0;
+            let upper = window_center + window_width / 2.0;
+            linear_map(value, lower, upper, out_max)
+        }
+        VoiFunction::Sigmoid => {
+            out_max / (1.0 + (-4.0 * (value - window_center) / window_width).exp())
+        }
+    }
+}
+
 #[pyfunction]
-fn window_level(pixels: Vec<f32>, window_width: f32, window_center: f32) -> PyResult<Vec<u8>> {
+fn window_level(
+    pixels: Vec<f32>,
+    window_width: f32,
+    window_center: f32,
+    rescale_slope: f32,
+    rescale_intercept: f32,
+    voi_function: &str,
+    invert: bool,
+    out_bits: u8,
+) -> PyResult<Vec<u8>> {
     if pixels.is_empty() {
         return Ok(vec![]);
     }
     let ww = window_width.max(1.0);
     let wc = window_center;
-    let lower = wc - ww / 2.0;
-    let upper = wc + ww / 2.0;
-    let scaled: Vec<u8> = pixels
-        .into_iter()
-        .map(|value| {
-            let clamped = if value < lower {
-                lower
-            } else if value > upper {
-                upper
-            } else {
-                value
-            };
-            let normalized = (clamped - lower) / (upper - lower);
-            (normalized * 255.0).clamp(0.0, 255.0) as u8
-        })
-        .collect();
+    let function = VoiFunction::parse(voi_function)?;
+    let out_max = match out_bits {
+        8 => 255.0,
+        16 => 65535.0,
+        other => {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "Unsupported out_bits: {other} (expected 8 or 16)"
+            )))
+        }
+    };
+
+    let mut scaled = Vec::with_capacity(pixels.len() * (out_bits as usize / 8));
+    for pixel in pixels {
+        let modality_value = apply_modality_lut(pixel, rescale_slope, rescale_intercept);
+        let mut voi_value = apply_voi(modality_value, ww, wc, out_max, &function).clamp(0.0, out_max);
+        if invert {
+            voi_value = out_max - voi_value;
+        }
+        match out_bits {
+            8 => scaled.push(voi_value as u8),
+            16 => scaled.extend_from_slice(&(voi_value as u16).to_le_bytes()),
+            _ => unreachable!("validated above"),
+        }
+    }
     Ok(scaled)
 }
 
@@ -95,3 +161,61 @@ fn noctis_dicom_core(_py: Python<'_>, module: &PyModule) -> PyResult<()> {
     module.add_function(wrap_pyfunction!(window_level, module)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modality_lut_rescales_stored_pixel() {
+        // CT Hounsfield units: slope 1, intercept -1024.
+        assert_eq!(apply_modality_lut(1024.0, 1.0, -1024.0), 0.0);
+        assert_eq!(apply_modality_lut(0.0, 2.0, 100.0), 100.0);
+    }
+
+    #[test]
+    fn linear_map_clamps_and_scales_to_out_max() {
+        assert_eq!(linear_map(-10.0, 0.0, 100.0, 255.0), 0.0);
+        assert_eq!(linear_map(200.0, 0.0, 100.0, 255.0), 255.0);
+        assert_eq!(linear_map(50.0, 0.0, 100.0, 255.0), 127.5);
+    }
+
+    #[test]
+    fn apply_voi_linear_matches_dicom_window_bounds() {
+        // LINEAR per PS3.3 C.11.2.1.2: lower = wc - 0.5 - (ww-1)/2.
+        let value = apply_voi(100.0, 200.0, 100.0, 255.0, &VoiFunction::Linear);
+        assert_eq!(value, linear_map(100.0, 0.0, 199.0, 255.0));
+    }
+
+    #[test]
+    fn apply_voi_linear_exact_matches_dicom_window_bounds() {
+        // LINEAR_EXACT per PS3.3 C.11.2.1.3.1: lower = wc - ww/2.
+        let value = apply_voi(100.0, 200.0, 100.0, 255.0, &VoiFunction::LinearExact);
+        assert_eq!(value, linear_map(100.0, 0.0, 200.0, 255.0));
+    }
+
+    #[test]
+    fn apply_voi_sigmoid_is_out_max_over_two_at_window_center() {
+        let value = apply_voi(100.0, 200.0, 100.0, 255.0, &VoiFunction::Sigmoid);
+        assert!((value - 127.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn apply_voi_sigmoid_saturates_away_from_window_center() {
+        let high = apply_voi(10_000.0, 200.0, 100.0, 255.0, &VoiFunction::Sigmoid);
+        let low = apply_voi(-10_000.0, 200.0, 100.0, 255.0, &VoiFunction::Sigmoid);
+        assert!(high > 254.0);
+        assert!(low < 1.0);
+    }
+
+    #[test]
+    fn voi_function_parse_accepts_known_names_and_rejects_others() {
+        assert!(matches!(VoiFunction::parse("LINEAR"), Ok(VoiFunction::Linear)));
+        assert!(matches!(
+            VoiFunction::parse("LINEAR_EXACT"),
+            Ok(VoiFunction::LinearExact)
+        ));
+        assert!(matches!(VoiFunction::parse("SIGMOID"), Ok(VoiFunction::Sigmoid)));
+        assert!(VoiFunction::parse("BOGUS").is_err());
+    }
+}
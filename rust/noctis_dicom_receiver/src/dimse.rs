@@ -0,0 +1,257 @@
+//! Minimal DIMSE command-set codec and Part-10 file assembly.
+//!
+//! The command set carried in P-DATA-TF command PDVs is always encoded as
+//! Implicit VR Little Endian regardless of the negotiated presentation
+//! context, so it is decoded independently of the main dataset transfer
+//! syntax handling that lives in `dicom_object`/`dicom_encoding`.
+
+use anyhow::{bail, Result};
+
+const PREAMBLE_LEN: usize = 128;
+const DICM_MAGIC: &[u8; 4] = b"DICM";
+
+pub const COMMAND_FIELD_C_STORE_RQ: u16 = 0x0001;
+pub const COMMAND_FIELD_C_STORE_RSP: u16 = 0x8001;
+
+/// DIMSE status codes relevant to a C-STORE-RSP, per PS3.7 Annex C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DimseStatus {
+    Success,
+    OutOfResources,
+    ProcessingFailure,
+}
+
+impl DimseStatus {
+    fn code(self) -> u16 {
+        match self {
+            DimseStatus::Success => 0x0000,
+            DimseStatus::OutOfResources => 0xA700,
+            DimseStatus::ProcessingFailure => 0xC000,
+        }
+    }
+}
+
+/// Fields pulled out of a DIMSE command set that the receiver cares about.
+#[derive(Debug, Clone, Default)]
+pub struct CommandSet {
+    pub command_field: Option<u16>,
+    pub message_id: Option<u16>,
+    pub message_id_being_responded_to: Option<u16>,
+    pub affected_sop_class_uid: Option<String>,
+    pub affected_sop_instance_uid: Option<String>,
+}
+
+/// Decodes the group-0x0000 command elements out of a reassembled command
+/// PDV payload.
+pub fn parse_command_set(bytes: &[u8]) -> Result<CommandSet> {
+    let mut cursor = bytes;
+    let mut set = CommandSet::default();
+    while !cursor.is_empty() {
+        if cursor.len() < 8 {
+            bail!("Truncated command element header");
+        }
+        let group = u16::from_le_bytes([cursor[0], cursor[1]]);
+        let element = u16::from_le_bytes([cursor[2], cursor[3]]);
+        let len = u32::from_le_bytes([cursor[4], cursor[5], cursor[6], cursor[7]]) as usize;
+        cursor = &cursor[8..];
+        if cursor.len() < len {
+            bail!("Truncated command element ({group:04X},{element:04X})");
+        }
+        let value = &cursor[..len];
+        cursor = &cursor[len..];
+        if group != 0x0000 {
+            continue;
+        }
+        match element {
+            0x0100 => set.command_field = read_u16(value),
+            0x0110 => set.message_id = read_u16(value),
+            0x0120 => set.message_id_being_responded_to = read_u16(value),
+            0x0002 => set.affected_sop_class_uid = Some(read_uid(value)),
+            0x1000 => set.affected_sop_instance_uid = Some(read_uid(value)),
+            _ => {}
+        }
+    }
+    Ok(set)
+}
+
+fn read_u16(value: &[u8]) -> Option<u16> {
+    (value.len() >= 2).then(|| u16::from_le_bytes([value[0], value[1]]))
+}
+
+fn read_uid(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_end_matches(['\0', ' '])
+        .to_string()
+}
+
+fn pad_even(mut bytes: Vec<u8>) -> Vec<u8> {
+    if bytes.len() % 2 != 0 {
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn write_command_element_u16(buf: &mut Vec<u8>, group: u16, element: u16, value: u16) {
+    buf.extend_from_slice(&group.to_le_bytes());
+    buf.extend_from_slice(&element.to_le_bytes());
+    buf.extend_from_slice(&2u32.to_le_bytes());
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_command_element_uid(buf: &mut Vec<u8>, group: u16, element: u16, uid: &str) {
+    let value = pad_even(uid.as_bytes().to_vec());
+    buf.extend_from_slice(&group.to_le_bytes());
+    buf.extend_from_slice(&element.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&value);
+}
+
+/// Builds the command set (Implicit VR Little Endian) for a C-STORE-RSP
+/// responding to the given message, echoing back the SOP class/instance
+/// and reporting `status`. `sop_class_uid`/`sop_instance_uid` are omitted
+/// from the response when the request's command set didn't carry them
+/// (e.g. a malformed C-STORE-RQ being failed out).
+pub fn build_c_store_rsp(
+    message_id_being_responded_to: u16,
+    sop_class_uid: Option<&str>,
+    sop_instance_uid: Option<&str>,
+    status: DimseStatus,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(sop_class_uid) = sop_class_uid {
+        write_command_element_uid(&mut buf, 0x0000, 0x0002, sop_class_uid);
+    }
+    write_command_element_u16(&mut buf, 0x0000, 0x0100, COMMAND_FIELD_C_STORE_RSP);
+    write_command_element_u16(
+        &mut buf,
+        0x0000,
+        0x0120,
+        message_id_being_responded_to,
+    );
+    write_command_element_u16(&mut buf, 0x0000, 0x0800, 0x0101); // CommandDataSetType: none
+    write_command_element_u16(&mut buf, 0x0000, 0x0900, status.code());
+    if let Some(sop_instance_uid) = sop_instance_uid {
+        write_command_element_uid(&mut buf, 0x0000, 0x1000, sop_instance_uid);
+    }
+    buf
+}
+
+fn write_meta_element(buf: &mut Vec<u8>, element: u16, vr: &[u8; 2], value: &[u8]) {
+    buf.extend_from_slice(&0x0002u16.to_le_bytes());
+    buf.extend_from_slice(&element.to_le_bytes());
+    buf.extend_from_slice(vr);
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn write_meta_uid(buf: &mut Vec<u8>, element: u16, uid: &str) {
+    write_meta_element(buf, element, b"UI", &pad_even(uid.as_bytes().to_vec()));
+}
+
+/// (0002,0001) FileMetaInformationVersion, a mandatory 2-byte `OB` value
+/// fixed to `\x00\x01` per PS3.10.
+fn write_meta_information_version(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&0x0002u16.to_le_bytes());
+    buf.extend_from_slice(&0x0001u16.to_le_bytes());
+    buf.extend_from_slice(b"OB");
+    buf.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    buf.extend_from_slice(&2u32.to_le_bytes());
+    buf.extend_from_slice(&[0x00, 0x01]);
+}
+
+/// Rebuilds a valid Part-10 file from a reassembled dataset fragment stream
+/// and the identifiers recovered from the command set, prepending the
+/// 128-byte preamble, `DICM` magic, and a File Meta Information group.
+pub fn build_part10(
+    dataset: &[u8],
+    transfer_syntax_uid: &str,
+    sop_class_uid: &str,
+    sop_instance_uid: &str,
+) -> Vec<u8> {
+    let mut meta = Vec::new();
+    write_meta_information_version(&mut meta);
+    write_meta_uid(&mut meta, 0x0002, sop_class_uid);
+    write_meta_uid(&mut meta, 0x0003, sop_instance_uid);
+    write_meta_uid(&mut meta, 0x0010, transfer_syntax_uid);
+    write_meta_uid(&mut meta, 0x0012, "1.2.826.0.1.3680043.9.7832.1.1");
+
+    let mut group_length = Vec::new();
+    write_meta_element(
+        &mut group_length,
+        0x0000,
+        b"UL",
+        &(meta.len() as u32).to_le_bytes(),
+    );
+
+    let mut out = Vec::with_capacity(PREAMBLE_LEN + 4 + group_length.len() + meta.len() + dataset.len());
+    out.extend(std::iter::repeat(0u8).take(PREAMBLE_LEN));
+    out.extend_from_slice(DICM_MAGIC);
+    out.extend_from_slice(&group_length);
+    out.extend_from_slice(&meta);
+    out.extend_from_slice(dataset);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_set_round_trips_through_build_c_store_rsp() {
+        let bytes = build_c_store_rsp(
+            42,
+            Some("1.2.840.10008.5.1.4.1.1.7"),
+            Some("1.2.3.4.5"),
+            DimseStatus::Success,
+        );
+        let parsed = parse_command_set(&bytes).expect("valid command set");
+        assert_eq!(parsed.command_field, Some(COMMAND_FIELD_C_STORE_RSP));
+        assert_eq!(parsed.message_id_being_responded_to, Some(42));
+        assert_eq!(
+            parsed.affected_sop_class_uid.as_deref(),
+            Some("1.2.840.10008.5.1.4.1.1.7")
+        );
+        assert_eq!(parsed.affected_sop_instance_uid.as_deref(), Some("1.2.3.4.5"));
+    }
+
+    #[test]
+    fn build_c_store_rsp_encodes_status_code() {
+        let bytes = build_c_store_rsp(1, None, None, DimseStatus::OutOfResources);
+        let parsed = parse_command_set(&bytes).expect("valid command set");
+        assert_eq!(parsed.affected_sop_class_uid, None);
+        assert_eq!(parsed.affected_sop_instance_uid, None);
+        assert_eq!(parsed.message_id_being_responded_to, Some(1));
+        // (0000,0900) Status, length 2, value 0xA700 (little endian).
+        let status_element = [0x00, 0x00, 0x00, 0x09, 0x02, 0x00, 0x00, 0x00, 0x00, 0xA7];
+        assert!(bytes.windows(status_element.len()).any(|w| w == status_element));
+    }
+
+    #[test]
+    fn parse_command_set_rejects_truncated_input() {
+        let err = parse_command_set(&[0x00, 0x00, 0x02, 0x00, 0x08, 0x00]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn build_part10_has_preamble_magic_and_meta_version() {
+        let dataset = b"dataset-bytes";
+        let part10 = build_part10(
+            dataset,
+            "1.2.840.10008.1.2.1",
+            "1.2.840.10008.5.1.4.1.1.7",
+            "1.2.3.4.5",
+        );
+
+        assert_eq!(&part10[0..PREAMBLE_LEN], &[0u8; PREAMBLE_LEN][..]);
+        assert_eq!(&part10[PREAMBLE_LEN..PREAMBLE_LEN + 4], DICM_MAGIC);
+        assert!(part10.ends_with(dataset));
+
+        // (0002,0001) FileMetaInformationVersion must immediately follow the
+        // group length element.
+        let version_tag_offset = PREAMBLE_LEN + 4 + 12; // preamble + DICM + group-length element
+        assert_eq!(
+            &part10[version_tag_offset..version_tag_offset + 4],
+            &[0x02, 0x00, 0x01, 0x00]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Dedup ledger and durable retry queue for forwarded ingests.
+//!
+//! A single process-wide sled database tracks which SOPInstanceUIDs have
+//! already been ingested, so re-sent studies are skipped before they're
+//! persisted and forwarded again, and holds a queue of `IngestPayload`s
+//! that failed to reach the FastAPI gateway so a background task can
+//! retry them with backoff across process restarts.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tracing::error;
+
+use crate::{FastApiForwarder, IngestPayload};
+
+const SEEN_TREE: &str = "seen_sop_instances";
+const PENDING_TREE: &str = "pending_deliveries";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+static DB: OnceLock<sled::Db> = OnceLock::new();
+
+pub struct Ledger {
+    seen: sled::Tree,
+    pending: sled::Tree,
+}
+
+impl Ledger {
+    /// Opens (or reuses) the process-wide sled database at `path`.
+    pub fn open(path: &str) -> Result<Self> {
+        let db = match DB.get() {
+            Some(db) => db,
+            None => {
+                let opened =
+                    sled::open(path).with_context(|| format!("Failed to open sled ledger at {path}"))?;
+                let _ = DB.set(opened);
+                DB.get().expect("just initialized above")
+            }
+        };
+        Ok(Self {
+            seen: db.open_tree(SEEN_TREE)?,
+            pending: db.open_tree(PENDING_TREE)?,
+        })
+    }
+
+    /// Checks whether `sop_instance_uid` has already been ingested,
+    /// without recording anything. Kept separate from `mark_seen` so a
+    /// persist failure never gets mistaken for a duplicate on retry.
+    pub fn is_seen(&self, sop_instance_uid: &str) -> Result<bool> {
+        Ok(self.seen.contains_key(sop_instance_uid)?)
+    }
+
+    /// Records `sop_instance_uid` as ingested. Callers must only call this
+    /// after the object has actually been persisted.
+    pub fn mark_seen(&self, sop_instance_uid: &str) -> Result<()> {
+        self.seen.insert(sop_instance_uid, &[])?;
+        Ok(())
+    }
+
+    /// Queues a payload that failed to forward for later retry.
+    pub fn enqueue_pending(&self, key: &str, payload: &IngestPayload) -> Result<()> {
+        let bytes = serde_json::to_vec(payload)?;
+        self.pending.insert(key, bytes)?;
+        Ok(())
+    }
+
+    /// Exposes the pending tree for the retry task without leaking sled
+    /// internals into the rest of the ledger's API.
+    pub fn pending_tree(&self) -> sled::Tree {
+        self.pending.clone()
+    }
+}
+
+/// Spawns a background task that drains the pending-deliveries tree with
+/// exponential backoff, removing entries once `FastApiForwarder::send`
+/// succeeds.
+pub fn spawn_retry_task(pending: sled::Tree, forwarder: Arc<FastApiForwarder>) {
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let mut drained_any = false;
+            for entry in pending.iter() {
+                let (key, bytes) = match entry {
+                    Ok(kv) => kv,
+                    Err(err) => {
+                        error!(?err, "Failed to read pending delivery entry");
+                        continue;
+                    }
+                };
+                let payload: IngestPayload = match serde_json::from_slice(&bytes) {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        error!(?err, "Dropping unparseable pending delivery");
+                        let _ = pending.remove(&key);
+                        continue;
+                    }
+                };
+                match forwarder.send(&payload).await {
+                    Ok(()) => {
+                        let _ = pending.remove(&key);
+                        drained_any = true;
+                    }
+                    Err(err) => {
+                        error!(?err, "Retry forward still failing");
+                    }
+                }
+            }
+            backoff = if drained_any {
+                INITIAL_BACKOFF
+            } else {
+                (backoff * 2).min(MAX_BACKOFF)
+            };
+            tokio::time::sleep(backoff).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StoredLocation;
+
+    fn sample_payload() -> IngestPayload {
+        IngestPayload {
+            location: StoredLocation("file:///tmp/1.2.3.4.dcm".to_string()),
+            calling_aet: "TESTAET".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            tls_peer_subject: None,
+            transfer_syntax_uid: "1.2.840.10008.1.2.1".to_string(),
+            original_size: 100,
+            stored_size: 100,
+            compressed: false,
+        }
+    }
+
+    // `Ledger::open` reuses a single process-wide `sled::Db` (see `DB`
+    // above) on every call after the first, regardless of the path passed
+    // in — only the first caller's path actually picks the on-disk
+    // location. Tests therefore can't rely on a fresh tempdir giving fresh
+    // trees; each test uses its own SOP Instance UID so they don't stomp on
+    // each other when run concurrently in the same test binary.
+    fn open_test_ledger() -> Ledger {
+        let dir = tempfile::tempdir().expect("tempdir");
+        Ledger::open(dir.path().join("ledger").to_str().unwrap()).expect("open ledger")
+    }
+
+    #[test]
+    fn is_seen_stays_false_until_mark_seen_is_called() {
+        let ledger = open_test_ledger();
+
+        assert!(!ledger.is_seen("1.2.3.4.1").expect("is_seen"));
+        ledger.mark_seen("1.2.3.4.1").expect("mark_seen");
+        assert!(ledger.is_seen("1.2.3.4.1").expect("is_seen"));
+    }
+
+    #[test]
+    fn is_seen_does_not_mark_as_a_side_effect() {
+        let ledger = open_test_ledger();
+
+        // Regression guard for the bug fixed in d527646: querying must never
+        // itself record the instance as seen.
+        for _ in 0..3 {
+            assert!(!ledger.is_seen("1.2.3.4.2").expect("is_seen"));
+        }
+    }
+
+    #[test]
+    fn enqueue_pending_round_trips_through_pending_tree() {
+        let ledger = open_test_ledger();
+        let payload = sample_payload();
+
+        ledger
+            .enqueue_pending("1.2.3.4.3", &payload)
+            .expect("enqueue_pending");
+
+        let pending = ledger.pending_tree();
+        let stored: IngestPayload = serde_json::from_slice(
+            &pending
+                .get("1.2.3.4.3")
+                .expect("read pending tree")
+                .expect("entry present"),
+        )
+        .expect("deserialize payload");
+        assert_eq!(stored.calling_aet, payload.calling_aet);
+        assert_eq!(stored.location.as_str(), payload.location.as_str());
+    }
+
+    #[test]
+    fn pending_tree_is_drained_once_the_entry_is_removed() {
+        let ledger = open_test_ledger();
+        let payload = sample_payload();
+
+        ledger
+            .enqueue_pending("1.2.3.4.4", &payload)
+            .expect("enqueue_pending");
+        let pending = ledger.pending_tree();
+        assert!(pending.get("1.2.3.4.4").expect("read pending tree").is_some());
+
+        pending.remove("1.2.3.4.4").expect("remove drained entry");
+        assert!(pending.get("1.2.3.4.4").expect("read pending tree").is_none());
+    }
+}
@@ -1,5 +1,5 @@
+use std::collections::HashMap;
 use std::env;
-use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -10,16 +10,37 @@ use dicom_ul::presentation::PresentationContextResult;
 use dicom_ul::pdu::{PDataValue, PDataValueType};
 use dicom_ul::{ServiceClassProviderExt, Uid};
 use reqwest::Client;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tokio::sync::Mutex;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-#[derive(Clone, Serialize)]
+mod dimse;
+mod ledger;
+mod storage;
+mod tls;
+mod transcode;
+
+use ledger::Ledger;
+use storage::{StorageBackend, StoredLocation};
+
+#[derive(Clone, Serialize, Deserialize)]
 struct IngestPayload {
-    file_path: PathBuf,
+    location: StoredLocation,
     calling_aet: String,
     remote_host: String,
+    /// Subject of the peer's TLS client certificate, when the association
+    /// was authenticated via mutual TLS.
+    tls_peer_subject: Option<String>,
+    /// Transfer syntax the object was actually stored in, after any
+    /// `NOCTIS_DICOM_STORE_TS` transcoding.
+    transfer_syntax_uid: String,
+    /// Size of the Part-10 object before compression.
+    original_size: usize,
+    /// Size of the bytes actually written to the storage backend.
+    stored_size: usize,
+    /// Whether `stored_size` is DEFLATE-compressed (`.dcm.z`).
+    compressed: bool,
 }
 
 struct FastApiForwarder {
@@ -41,25 +62,265 @@ impl FastApiForwarder {
     }
 }
 
+/// Fragments of an in-flight DIMSE message on a single presentation
+/// context, keyed separately for the command set and the dataset since
+/// both arrive as their own fragment streams.
+#[derive(Default)]
+struct Assembly {
+    command: Vec<u8>,
+    command_done: bool,
+    dataset: Vec<u8>,
+    dataset_done: bool,
+}
+
+impl Assembly {
+    fn is_complete(&self) -> bool {
+        self.command_done && self.dataset_done
+    }
+}
+
+/// Result of feeding one P-DATA-TF fragment through `accumulate`.
+enum AccumulateOutcome {
+    /// The message is still missing fragments.
+    Incomplete,
+    /// The message is complete; send this command PDV back as its
+    /// C-STORE-RSP.
+    Response(PDataValue),
+    /// The message could not be made sense of well enough to respond to
+    /// (e.g. no MessageID could be recovered); abort the association.
+    Abort,
+}
+
 struct NoctisReceiver {
-    storage_root: PathBuf,
-    forwarder: FastApiForwarder,
+    backend: Box<dyn StorageBackend>,
+    forwarder: Arc<FastApiForwarder>,
+    ledger: Ledger,
+    /// Target transfer syntax to transcode received objects into before
+    /// storage, if `NOCTIS_DICOM_STORE_TS` is set.
+    store_transfer_syntax: Option<String>,
+    /// DEFLATE level to store objects at, if deflated storage is enabled.
+    deflate_level: Option<u32>,
+    /// Transfer syntax accepted for each presentation context ID, recorded
+    /// once association negotiation completes.
+    transfer_syntaxes: Mutex<HashMap<u8, String>>,
+    /// In-progress fragment buffers, keyed by presentation context ID.
+    assemblies: Mutex<HashMap<u8, Assembly>>,
 }
 
 impl NoctisReceiver {
-    fn new(storage_root: PathBuf, forwarder: FastApiForwarder) -> Self {
+    fn new(
+        backend: Box<dyn StorageBackend>,
+        forwarder: Arc<FastApiForwarder>,
+        ledger: Ledger,
+        store_transfer_syntax: Option<String>,
+        deflate_level: Option<u32>,
+    ) -> Self {
         Self {
-            storage_root,
+            backend,
             forwarder,
+            ledger,
+            store_transfer_syntax,
+            deflate_level,
+            transfer_syntaxes: Mutex::new(HashMap::new()),
+            assemblies: Mutex::new(HashMap::new()),
         }
     }
 
-    async fn persist_dataset(&self, pdata: &PDataValue, calling_aet: &str) -> Result<PathBuf> {
-        let study_dir = self.storage_root.join(calling_aet);
-        fs::create_dir_all(&study_dir).await?;
-        let temp = tempfile::NamedTempFile::new_in(&study_dir)?;
-        tokio::fs::write(temp.path(), &pdata.data_fragment).await?;
-        Ok(temp.into_temp_path().to_path_buf())
+    /// Normalizes `part10` (transcoding and/or deflating it per config),
+    /// persists it to the storage backend, and reports the sizing/syntax
+    /// metadata the gateway needs to open it back up.
+    async fn persist_dataset(
+        &self,
+        calling_aet: &str,
+        sop_instance_uid: &str,
+        transfer_syntax_uid: &str,
+        part10: &[u8],
+    ) -> Result<(StoredLocation, String, usize, usize, bool)> {
+        let (part10, transfer_syntax_uid) = match &self.store_transfer_syntax {
+            Some(target) => (
+                transcode::transcode(part10, transfer_syntax_uid, target)
+                    .context("Failed to transcode object to configured transfer syntax")?,
+                target.clone(),
+            ),
+            None => (part10.to_vec(), transfer_syntax_uid.to_string()),
+        };
+        let original_size = part10.len();
+
+        let (bytes, compressed, extension) = match self.deflate_level {
+            Some(level) => (
+                transcode::deflate(&part10, level).context("Failed to deflate object")?,
+                true,
+                transcode::DEFLATED_SUFFIX,
+            ),
+            None => (part10, false, ".dcm"),
+        };
+        let stored_size = bytes.len();
+
+        let key = format!("{calling_aet}/{sop_instance_uid}{extension}");
+        let location = self.backend.put(&key, &bytes).await?;
+        Ok((location, transfer_syntax_uid, original_size, stored_size, compressed))
+    }
+
+    /// Buffers one P-DATA-TF fragment and, once both the command and
+    /// dataset streams for the message are complete, reassembles and
+    /// persists the resulting Part-10 object. Failures are not propagated
+    /// as bare errors: once a MessageID is known, each fallible step is
+    /// mapped to a DIMSE status and answered with a C-STORE-RSP; fragments
+    /// for an already-ended stream, an unparseable command set, or a
+    /// missing MessageID abort the association instead, so a malformed
+    /// request never leaves the SCU hanging.
+    async fn accumulate(
+        &self,
+        value: PDataValue,
+        calling_aet: &str,
+        remote_host: &str,
+        tls_peer_subject: Option<&str>,
+    ) -> AccumulateOutcome {
+        let pc_id = value.presentation_context_id;
+        let is_last = value.is_last;
+
+        let assembly = {
+            let mut assemblies = self.assemblies.lock().await;
+            let assembly = assemblies.entry(pc_id).or_default();
+            // Fragments are keyed by presentation-context ID alone, which is
+            // only safe because this SCP never reads the next PDU before
+            // answering the in-flight C-STORE-RQ (no asynchronous operations
+            // window). If that ever changes, a second message's fragments
+            // could interleave with this one's on the same pc_id; detect
+            // that here rather than silently concatenating two messages'
+            // fragments into one Part-10 object.
+            let stream_already_ended = match value.pdv_type {
+                PDataValueType::Command => assembly.command_done,
+                PDataValueType::Data => assembly.dataset_done,
+            };
+            if stream_already_ended {
+                warn!(
+                    pc_id,
+                    "Received a fragment for a stream that already ended on this \
+                     presentation context; more than one outstanding C-STORE-RQ \
+                     is not supported, aborting association"
+                );
+                assemblies.remove(&pc_id);
+                return AccumulateOutcome::Abort;
+            }
+            match value.pdv_type {
+                PDataValueType::Command => {
+                    assembly.command.extend_from_slice(&value.data_fragment);
+                    assembly.command_done = is_last;
+                }
+                PDataValueType::Data => {
+                    assembly.dataset.extend_from_slice(&value.data_fragment);
+                    assembly.dataset_done = is_last;
+                }
+            }
+            if !assembly.is_complete() {
+                return AccumulateOutcome::Incomplete;
+            }
+            assemblies.remove(&pc_id).expect("just inserted above")
+        };
+
+        let command = match dimse::parse_command_set(&assembly.command) {
+            Ok(command) => command,
+            Err(err) => {
+                warn!(?err, "Failed to parse DIMSE command set; aborting association");
+                return AccumulateOutcome::Abort;
+            }
+        };
+        let Some(message_id) = command.message_id else {
+            warn!("C-STORE-RQ missing MessageID; aborting association");
+            return AccumulateOutcome::Abort;
+        };
+        let sop_class_uid = command.affected_sop_class_uid;
+        let sop_instance_uid = command.affected_sop_instance_uid;
+        let transfer_syntax_uid = self.transfer_syntaxes.lock().await.get(&pc_id).cloned();
+
+        let (sop_class_uid, sop_instance_uid, transfer_syntax_uid) =
+            match (sop_class_uid.clone(), sop_instance_uid.clone(), transfer_syntax_uid) {
+                (Some(class_uid), Some(instance_uid), Some(ts)) => (class_uid, instance_uid, ts),
+                _ => {
+                    warn!(
+                        ?sop_class_uid,
+                        ?sop_instance_uid,
+                        "C-STORE-RQ missing required identifiers or unnegotiated presentation context"
+                    );
+                    let response = dimse::build_c_store_rsp(
+                        message_id,
+                        sop_class_uid.as_deref(),
+                        sop_instance_uid.as_deref(),
+                        dimse::DimseStatus::ProcessingFailure,
+                    );
+                    return AccumulateOutcome::Response(PDataValue {
+                        presentation_context_id: pc_id,
+                        pdv_type: PDataValueType::Command,
+                        is_last: true,
+                        data_fragment: response,
+                    });
+                }
+            };
+
+        let status = match self.ledger.is_seen(&sop_instance_uid) {
+            Ok(true) => {
+                info!(%sop_instance_uid, "Skipping already-ingested duplicate");
+                dimse::DimseStatus::Success
+            }
+            Ok(false) => {
+                let part10 = dimse::build_part10(
+                    &assembly.dataset,
+                    &transfer_syntax_uid,
+                    &sop_class_uid,
+                    &sop_instance_uid,
+                );
+
+                match self
+                    .persist_dataset(calling_aet, &sop_instance_uid, &transfer_syntax_uid, &part10)
+                    .await
+                {
+                    Ok((location, transfer_syntax_uid, original_size, stored_size, compressed)) => {
+                        if let Err(err) = self.ledger.mark_seen(&sop_instance_uid) {
+                            error!(?err, "Failed to record instance in dedup ledger");
+                        }
+                        let payload = IngestPayload {
+                            location,
+                            calling_aet: calling_aet.to_string(),
+                            remote_host: remote_host.to_string(),
+                            tls_peer_subject: tls_peer_subject.map(str::to_string),
+                            transfer_syntax_uid,
+                            original_size,
+                            stored_size,
+                            compressed,
+                        };
+                        if let Err(err) = self.forwarder.send(&payload).await {
+                            warn!(?err, "Failed to forward ingest payload, queuing for retry");
+                            if let Err(err) = self.ledger.enqueue_pending(&sop_instance_uid, &payload) {
+                                error!(?err, "Failed to queue ingest payload for retry");
+                            }
+                        }
+                        dimse::DimseStatus::Success
+                    }
+                    Err(err) => {
+                        error!(?err, "Failed to persist dataset");
+                        dimse::DimseStatus::OutOfResources
+                    }
+                }
+            }
+            Err(err) => {
+                error!(?err, "Failed to query dedup ledger");
+                dimse::DimseStatus::ProcessingFailure
+            }
+        };
+
+        let response = dimse::build_c_store_rsp(
+            message_id,
+            Some(&sop_class_uid),
+            Some(&sop_instance_uid),
+            status,
+        );
+        AccumulateOutcome::Response(PDataValue {
+            presentation_context_id: pc_id,
+            pdv_type: PDataValueType::Command,
+            is_last: true,
+            data_fragment: response,
+        })
     }
 }
 
@@ -67,12 +328,13 @@ impl NoctisReceiver {
 impl ServiceClassProvider for NoctisReceiver {
     async fn handle_presentation_context(&self, result: PresentationContextResult) {
         info!(?result, "Presentation context negotiated");
+        self.transfer_syntaxes
+            .lock()
+            .await
+            .insert(result.id, result.transfer_syntax);
     }
 
     async fn handle_p_data(&self, value: PDataValue, assoc: &mut dicom_ul::association::Association) {
-        if value.pdv_type != PDataValueType::Data {
-            return;
-        }
         let calling_aet = assoc
             .caller_ae_title()
             .unwrap_or_else(|_| "UNKNOWN".into())
@@ -82,18 +344,25 @@ impl ServiceClassProvider for NoctisReceiver {
             .peer_addr()
             .map(|addr| addr.ip().to_string())
             .unwrap_or_else(|_| "unknown".into());
-        match self.persist_dataset(&value, &calling_aet).await {
-            Ok(file_path) => {
-                let payload = IngestPayload {
-                    file_path: file_path.clone(),
-                    calling_aet: calling_aet.clone(),
-                    remote_host,
-                };
-                if let Err(err) = self.forwarder.send(&payload).await {
-                    error!(?err, "Failed to forward ingest payload");
+        let tls_peer_subject = assoc
+            .peer_certificate()
+            .and_then(|cert| tls::peer_subject(cert));
+
+        match self
+            .accumulate(value, &calling_aet, &remote_host, tls_peer_subject.as_deref())
+            .await
+        {
+            AccumulateOutcome::Response(response) => {
+                if let Err(err) = assoc.send_pdv(response).await {
+                    error!(?err, "Failed to send C-STORE-RSP");
+                }
+            }
+            AccumulateOutcome::Incomplete => {}
+            AccumulateOutcome::Abort => {
+                if let Err(err) = assoc.abort().await {
+                    error!(?err, "Failed to abort association after unrecoverable C-STORE-RQ");
                 }
             }
-            Err(err) => error!(?err, "Failed to persist dataset"),
         }
     }
 }
@@ -111,19 +380,48 @@ async fn main() -> Result<()> {
     let aet = env::var("NOCTIS_DICOM_AET").unwrap_or_else(|_| "NOCTIS_SCP".into());
     let api_url = env::var("NOCTIS_FASTAPI_URL")
         .unwrap_or_else(|_| "http://127.0.0.1:9000/dicom/ingest".into());
-    let storage_root = PathBuf::from(
-        env::var("NOCTIS_DICOM_STORAGE").unwrap_or_else(|_| "./media/dicom/received".into()),
-    );
-    fs::create_dir_all(&storage_root).await?;
+    let storage_url = env::var("NOCTIS_DICOM_STORAGE")
+        .unwrap_or_else(|_| "file://./media/dicom/received".into());
+    let backend = storage::from_url(&storage_url).context("Failed to set up storage backend")?;
+    let ledger_path =
+        env::var("NOCTIS_LEDGER_PATH").unwrap_or_else(|_| "./media/dicom/ledger".into());
+    let ledger = Ledger::open(&ledger_path).context("Failed to open dedup ledger")?;
 
-    let forwarder = FastApiForwarder {
+    let forwarder = Arc::new(FastApiForwarder {
         client: Client::new(),
         endpoint: api_url,
+    });
+    ledger::spawn_retry_task(ledger.pending_tree(), forwarder.clone());
+
+    let store_transfer_syntax = env::var("NOCTIS_DICOM_STORE_TS").ok();
+    let deflate_level = match env::var("NOCTIS_DICOM_DEFLATE") {
+        Ok(value) if value == "0" => None,
+        Ok(_) => {
+            let level = match env::var("NOCTIS_DICOM_DEFLATE_LEVEL").ok() {
+                Some(raw) => raw.parse().context("Invalid NOCTIS_DICOM_DEFLATE_LEVEL")?,
+                None => 6,
+            };
+            anyhow::ensure!(
+                level <= 9,
+                "NOCTIS_DICOM_DEFLATE_LEVEL must be between 0 and 9, got {level}"
+            );
+            Some(level)
+        }
+        Err(_) => None,
     };
-    let provider = NoctisReceiver::new(storage_root, forwarder);
+    let provider = NoctisReceiver::new(backend, forwarder, ledger, store_transfer_syntax, deflate_level);
 
-    info!(%aet, %port, "Starting Rust DICOM receiver");
-    let mut server = Server::new(aet.into_bytes(), ([0, 0, 0, 0], port).into());
+    let tls_config = tls::server_config_from_env().context("Failed to set up DICOM-TLS")?;
+    let mut server = match tls_config {
+        Some(config) => {
+            info!(%aet, %port, "Starting Rust DICOM receiver (DICOM-TLS)");
+            Server::new_tls(aet.into_bytes(), ([0, 0, 0, 0], port).into(), Arc::new(config))
+        }
+        None => {
+            info!(%aet, %port, "Starting Rust DICOM receiver");
+            Server::new(aet.into_bytes(), ([0, 0, 0, 0], port).into())
+        }
+    };
     server
         .run(provider)
         .await
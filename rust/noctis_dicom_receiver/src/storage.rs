@@ -0,0 +1,350 @@
+//! Pluggable storage for reassembled Part-10 objects.
+//!
+//! The backend is selected at startup from the scheme of
+//! `NOCTIS_DICOM_STORAGE`: `file://` keeps today's local-disk behavior,
+//! `gs://` uploads to a Google Cloud Storage bucket, and `dav://`/`davs://`
+//! writes to a WebDAV server. All three report back a backend-agnostic
+//! [`StoredLocation`] URI so the FastAPI gateway can fetch the object
+//! without caring where it actually lives.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::RwLock;
+
+/// Margin before actual expiry at which a cached access token is treated
+/// as stale and proactively refreshed.
+const TOKEN_REFRESH_MARGIN_SECS: u64 = 60;
+const GCS_READ_WRITE_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Backend-agnostic pointer to a persisted object, carried in
+/// `IngestPayload` as a URI string (`file://...`, `gs://bucket/key`,
+/// or, for WebDAV, the `http://`/`https://` URL of the object).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLocation(pub String);
+
+impl StoredLocation {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Persists `bytes` under `key` (a caller-chosen relative path such as
+    /// `<calling_aet>/<sop_instance_uid>.dcm`) and returns where it landed.
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<StoredLocation>;
+}
+
+/// Objects land under a root directory on local disk, at the path given by
+/// `key`; missing parent directories are created.
+pub struct LocalFsBackend {
+    root: PathBuf,
+}
+
+impl LocalFsBackend {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageBackend for LocalFsBackend {
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<StoredLocation> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, bytes).await?;
+        Ok(StoredLocation(format!("file://{}", path.display())))
+    }
+}
+
+/// The fields used out of a GCP service-account JSON key file.
+#[derive(Debug, Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    iss: String,
+    scope: String,
+    aud: String,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct CachedToken {
+    access_token: String,
+    expires_at_unix: u64,
+}
+
+/// Exchanges a service-account JSON key for short-lived OAuth2 access
+/// tokens (via the JWT-bearer grant), refreshing them shortly before they
+/// expire rather than reusing one for the process lifetime.
+struct GcsTokenProvider {
+    client: Client,
+    key: ServiceAccountKey,
+    cached: RwLock<Option<CachedToken>>,
+}
+
+impl GcsTokenProvider {
+    fn from_key_file(path: &str) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read GCS service account key at {path}"))?;
+        let key: ServiceAccountKey =
+            serde_json::from_str(&raw).context("Invalid GCS service account key JSON")?;
+        Ok(Self {
+            client: Client::new(),
+            key,
+            cached: RwLock::new(None),
+        })
+    }
+
+    async fn access_token(&self) -> Result<String> {
+        let now = unix_now();
+        if let Some(cached) = self.cached.read().await.as_ref() {
+            if cached.expires_at_unix > now + TOKEN_REFRESH_MARGIN_SECS {
+                return Ok(cached.access_token.clone());
+            }
+        }
+
+        let token = self.exchange_jwt_for_token(now).await?;
+        let expires_at_unix = now + token.expires_in;
+        *self.cached.write().await = Some(CachedToken {
+            access_token: token.access_token.clone(),
+            expires_at_unix,
+        });
+        Ok(token.access_token)
+    }
+
+    async fn exchange_jwt_for_token(&self, now: u64) -> Result<TokenResponse> {
+        let claims = Claims {
+            iss: self.key.client_email.clone(),
+            scope: GCS_READ_WRITE_SCOPE.to_string(),
+            aud: self.key.token_uri.clone(),
+            iat: now,
+            exp: now + 3600,
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(self.key.private_key.as_bytes())
+            .context("Invalid GCS service account private key")?;
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)
+            .context("Failed to sign JWT assertion")?;
+
+        self.client
+            .post(&self.key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .context("Failed to exchange JWT assertion for an access token")?
+            .error_for_status()
+            .context("Token endpoint rejected JWT assertion")?
+            .json()
+            .await
+            .context("Invalid token endpoint response")
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before UNIX epoch")
+        .as_secs()
+}
+
+/// Uploads via the GCS JSON API's simple upload endpoint, authenticated
+/// with a service-account bearer token that is refreshed as it nears
+/// expiry rather than reused for the process lifetime.
+pub struct GcsBackend {
+    client: Client,
+    bucket: String,
+    tokens: GcsTokenProvider,
+}
+
+impl GcsBackend {
+    pub fn new(bucket: String, service_account_key_path: &str) -> Result<Self> {
+        Ok(Self {
+            client: Client::new(),
+            bucket,
+            tokens: GcsTokenProvider::from_key_file(service_account_key_path)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageBackend for GcsBackend {
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<StoredLocation> {
+        let access_token = self.tokens.access_token().await?;
+        let url = format!(
+            "https://storage.googleapis.com/upload/storage/v1/b/{}/o?uploadType=media&name={}",
+            self.bucket,
+            urlencoding::encode(key)
+        );
+        self.client
+            .post(&url)
+            .bearer_auth(access_token)
+            .header("Content-Type", "application/dicom")
+            .body(bytes.to_vec())
+            .send()
+            .await
+            .context("Failed to upload object to GCS")?
+            .error_for_status()
+            .context("GCS rejected upload")?;
+        Ok(StoredLocation(format!("gs://{}/{}", self.bucket, key)))
+    }
+}
+
+/// Writes via a `PUT` request against a WebDAV collection.
+pub struct WebDavBackend {
+    client: Client,
+    base_url: String,
+}
+
+impl WebDavBackend {
+    pub fn new(base_url: String) -> Self {
+        Self {
+            client: Client::new(),
+            base_url,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageBackend for WebDavBackend {
+    async fn put(&self, key: &str, bytes: &[u8]) -> Result<StoredLocation> {
+        if let Some((collection, _)) = key.rsplit_once('/') {
+            self.ensure_collection(collection).await?;
+        }
+        let url = format!("{}/{}", self.base_url.trim_end_matches('/'), key);
+        self.client
+            .put(&url)
+            .body(bytes.to_vec())
+            .send()
+            .await
+            .context("Failed to PUT object to WebDAV server")?
+            .error_for_status()
+            .context("WebDAV server rejected upload")?;
+        Ok(StoredLocation(url))
+    }
+}
+
+impl WebDavBackend {
+    /// Issues `MKCOL` for `collection` so a `PUT` into it doesn't 409
+    /// against a server that refuses to create missing parent collections
+    /// implicitly. A 405 (already exists) is expected and not an error.
+    async fn ensure_collection(&self, collection: &str) -> Result<()> {
+        let url = format!("{}/{}", self.base_url.trim_end_matches('/'), collection);
+        let response = self
+            .client
+            .request(reqwest::Method::from_bytes(b"MKCOL").unwrap(), &url)
+            .send()
+            .await
+            .context("Failed to MKCOL WebDAV collection")?;
+        if response.status().is_success() || response.status() == reqwest::StatusCode::METHOD_NOT_ALLOWED {
+            return Ok(());
+        }
+        response
+            .error_for_status()
+            .context("WebDAV server rejected collection creation")?;
+        Ok(())
+    }
+}
+
+/// Builds the configured backend from the `NOCTIS_DICOM_STORAGE` URL.
+pub fn from_url(url: &str) -> Result<Box<dyn StorageBackend>> {
+    if let Some(root) = url.strip_prefix("file://") {
+        return Ok(Box::new(LocalFsBackend::new(PathBuf::from(root))));
+    }
+    if let Some(bucket) = url.strip_prefix("gs://") {
+        let key_path = std::env::var("NOCTIS_GCS_SERVICE_ACCOUNT_KEY")
+            .context("NOCTIS_GCS_SERVICE_ACCOUNT_KEY is required for gs:// storage")?;
+        return Ok(Box::new(GcsBackend::new(
+            bucket.trim_end_matches('/').to_string(),
+            &key_path,
+        )?));
+    }
+    if let Some(rest) = url.strip_prefix("dav://") {
+        return Ok(Box::new(WebDavBackend::new(format!("http://{rest}"))));
+    }
+    if let Some(rest) = url.strip_prefix("davs://") {
+        return Ok(Box::new(WebDavBackend::new(format!("https://{rest}"))));
+    }
+    bail!("Unsupported NOCTIS_DICOM_STORAGE scheme: {url}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_fs_backend_round_trips_bytes_and_reports_file_uri() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let backend = LocalFsBackend::new(dir.path().to_path_buf());
+
+        let location = backend
+            .put("AET1/1.2.3.4.dcm", b"dataset-bytes")
+            .await
+            .expect("put");
+
+        let path = dir.path().join("AET1/1.2.3.4.dcm");
+        assert_eq!(location.as_str(), format!("file://{}", path.display()));
+        assert_eq!(
+            tokio::fs::read(&path).await.expect("read back"),
+            b"dataset-bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn local_fs_backend_creates_missing_parent_directories() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let backend = LocalFsBackend::new(dir.path().to_path_buf());
+
+        backend
+            .put("nested/deeper/1.2.3.4.dcm", b"dataset-bytes")
+            .await
+            .expect("put");
+
+        assert!(dir.path().join("nested/deeper/1.2.3.4.dcm").exists());
+    }
+
+    #[test]
+    fn from_url_dispatches_file_scheme() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let url = format!("file://{}", dir.path().display());
+        assert!(from_url(&url).is_ok());
+    }
+
+    #[test]
+    fn from_url_dispatches_dav_and_davs_schemes() {
+        assert!(from_url("dav://example.test/dicom").is_ok());
+        assert!(from_url("davs://example.test/dicom").is_ok());
+    }
+
+    #[test]
+    fn from_url_requires_service_account_key_for_gs_scheme() {
+        std::env::remove_var("NOCTIS_GCS_SERVICE_ACCOUNT_KEY");
+        assert!(from_url("gs://some-bucket").is_err());
+    }
+
+    #[test]
+    fn from_url_rejects_unsupported_scheme() {
+        assert!(from_url("ftp://example.test").is_err());
+    }
+}
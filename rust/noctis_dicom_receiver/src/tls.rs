@@ -0,0 +1,69 @@
+//! Optional DICOM-TLS (secure transport) support for the listener.
+//!
+//! `NOCTIS_DICOM_TLS_CERT`/`NOCTIS_DICOM_TLS_KEY` enable TLS on the
+//! listener; an additional `NOCTIS_DICOM_TLS_CA` turns on mutual TLS,
+//! requiring calling AEs to present a certificate signed by that CA before
+//! an association is accepted.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{Context, Result};
+use rustls::server::{AllowAnyAuthenticatedClient, NoClientAuth};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::{certs, pkcs8_private_keys};
+
+/// Builds a `rustls::ServerConfig` from the environment, or returns `None`
+/// when TLS is not configured and the listener should stay plain TCP.
+pub fn server_config_from_env() -> Result<Option<ServerConfig>> {
+    let (cert_path, key_path) = match (
+        std::env::var("NOCTIS_DICOM_TLS_CERT"),
+        std::env::var("NOCTIS_DICOM_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+
+    let cert_chain = load_certs(&cert_path)?;
+    let private_key = load_private_key(&key_path)?;
+
+    let client_auth = match std::env::var("NOCTIS_DICOM_TLS_CA") {
+        Ok(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&ca_path)? {
+                roots.add(&cert).context("Invalid CA certificate")?;
+            }
+            AllowAnyAuthenticatedClient::new(roots).boxed()
+        }
+        Err(_) => NoClientAuth::boxed(),
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(cert_chain, private_key)
+        .context("Invalid TLS certificate/key pair")?;
+
+    Ok(Some(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Failed to open {path}"))?);
+    let raw = certs(&mut reader).with_context(|| format!("Failed to parse certificates in {path}"))?;
+    Ok(raw.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("Failed to open {path}"))?);
+    let mut keys =
+        pkcs8_private_keys(&mut reader).with_context(|| format!("Failed to parse private key in {path}"))?;
+    let key = keys.pop().with_context(|| format!("No private key found in {path}"))?;
+    Ok(PrivateKey(key))
+}
+
+/// Extracts the subject of the peer certificate presented during a mutual
+/// TLS handshake, for recording alongside `calling_aet`/`remote_host`.
+pub fn peer_subject(cert: &Certificate) -> Option<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(parsed.subject().to_string())
+}
@@ -0,0 +1,39 @@
+//! Optional transfer-syntax transcoding and DEFLATE compression applied to
+//! a reassembled object before it reaches the configured `StorageBackend`.
+
+use std::io::{Cursor, Write};
+
+use anyhow::{Context, Result};
+use dicom_object::from_reader;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+/// Suffix used for objects stored in deflated form.
+pub const DEFLATED_SUFFIX: &str = ".dcm.z";
+
+/// Re-encodes a Part-10 byte stream into `target_transfer_syntax_uid`,
+/// returning the bytes unchanged if it already matches the source syntax.
+pub fn transcode(
+    part10: &[u8],
+    current_transfer_syntax_uid: &str,
+    target_transfer_syntax_uid: &str,
+) -> Result<Vec<u8>> {
+    if current_transfer_syntax_uid == target_transfer_syntax_uid {
+        return Ok(part10.to_vec());
+    }
+    let object =
+        from_reader(Cursor::new(part10)).context("Failed to parse Part-10 object for transcoding")?;
+    let mut out = Vec::new();
+    object
+        .write_all_with_ts(&mut out, target_transfer_syntax_uid)
+        .context("Failed to re-encode object in target transfer syntax")?;
+    Ok(out)
+}
+
+/// DEFLATE-compresses a Part-10 byte stream at the given compression level
+/// (0-9).
+pub fn deflate(part10: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(part10).context("Failed to deflate object")?;
+    encoder.finish().context("Failed to finalize deflate stream")
+}